@@ -0,0 +1,127 @@
+use crate::ntp_packet::KissODeathIdentifier;
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+/// How often stale per-client request history is pruned, bounding memory use.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Outcome of an access-control check for an incoming request.
+#[derive(Debug)]
+pub enum AccessDecision {
+    /// Serve the request normally.
+    Allow,
+    /// Refuse with a Kiss-o'-Death packet carrying this identifier.
+    Kiss(KissODeathIdentifier),
+}
+
+/// Per-client rate limiting and allow/deny access control, reporting decisions as
+/// [`KissODeathIdentifier`] values per RFC 5905.
+pub struct AccessControl {
+    max_requests_per_second: u32,
+    allow_list: Vec<IpAddr>,
+    deny_list: Vec<IpAddr>,
+    history: HashMap<IpAddr, VecDeque<Instant>>,
+    last_prune: Instant,
+}
+
+pub type SharedAccessControl = Arc<Mutex<AccessControl>>;
+
+impl AccessControl {
+    pub fn new(max_requests_per_second: u32, allow_list: Vec<IpAddr>, deny_list: Vec<IpAddr>) -> Self {
+        AccessControl {
+            max_requests_per_second,
+            allow_list,
+            deny_list,
+            history: HashMap::new(),
+            last_prune: Instant::now(),
+        }
+    }
+
+    /// Records a request from `addr` and decides whether it should be served or kissed off.
+    pub fn check(&mut self, addr: IpAddr) -> AccessDecision {
+        if self.deny_list.contains(&addr) {
+            return AccessDecision::Kiss(KissODeathIdentifier::DENY);
+        }
+        if !self.allow_list.is_empty() && !self.allow_list.contains(&addr) {
+            return AccessDecision::Kiss(KissODeathIdentifier::RSTR);
+        }
+
+        self.prune_if_due();
+
+        let now = Instant::now();
+        let window = self.history.entry(addr).or_default();
+        window.retain(|seen| now.duration_since(*seen) < Duration::from_secs(1));
+
+        if window.len() as u32 >= self.max_requests_per_second {
+            return AccessDecision::Kiss(KissODeathIdentifier::RATE);
+        }
+
+        window.push_back(now);
+        AccessDecision::Allow
+    }
+
+    fn prune_if_due(&mut self) {
+        let now = Instant::now();
+        if now.duration_since(self.last_prune) < PRUNE_INTERVAL {
+            return;
+        }
+        self.last_prune = now;
+        self.history.retain(|_, window| {
+            window.retain(|seen| now.duration_since(*seen) < Duration::from_secs(1));
+            !window.is_empty()
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn addr(octet: u8) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::new(127, 0, 0, octet))
+    }
+
+    #[test]
+    fn rate_limit_trips_at_configured_threshold() {
+        let mut access_control = AccessControl::new(2, vec![], vec![]);
+        let client = addr(1);
+
+        assert!(matches!(access_control.check(client), AccessDecision::Allow));
+        assert!(matches!(access_control.check(client), AccessDecision::Allow));
+        assert!(matches!(
+            access_control.check(client),
+            AccessDecision::Kiss(KissODeathIdentifier::RATE)
+        ));
+    }
+
+    #[test]
+    fn deny_list_takes_precedence_over_allow_list() {
+        let client = addr(1);
+        let mut access_control = AccessControl::new(10, vec![client], vec![client]);
+
+        assert!(matches!(
+            access_control.check(client),
+            AccessDecision::Kiss(KissODeathIdentifier::DENY)
+        ));
+    }
+
+    #[test]
+    fn pruning_drops_stale_per_client_history() {
+        let mut access_control = AccessControl::new(10, vec![], vec![]);
+        let client = addr(1);
+        access_control
+            .history
+            .entry(client)
+            .or_default()
+            .push_back(Instant::now() - Duration::from_secs(2));
+        access_control.last_prune = Instant::now() - PRUNE_INTERVAL - Duration::from_secs(1);
+
+        access_control.prune_if_due();
+
+        assert!(access_control.history.is_empty());
+    }
+}
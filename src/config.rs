@@ -0,0 +1,78 @@
+use clap::Parser;
+use std::net::{IpAddr, SocketAddr};
+use tracing::Level;
+
+/// Addresses the daemon listens on when no bind addresses are given on the command line: both
+/// IPv4 and IPv6 wildcard addresses, so the default deployment is dual-stack.
+const DEFAULT_BIND_ADDRS: &[&str] = &["0.0.0.0:123", "[::]:123"];
+
+/// Runtime configuration for the daemon, parsed from CLI arguments with environment-variable
+/// fallbacks so it can be deployed without recompiling.
+#[derive(Parser, Debug)]
+#[command(name = "simple_nium_time_protocol", about = "A minimal NTP server")]
+pub struct Config {
+    /// Addresses to listen on (host:port). Defaults to 0.0.0.0:123 and [::]:123 when none are given.
+    #[arg(env = "NIUM_NTPD_BIND", value_delimiter = ' ')]
+    bind: Vec<SocketAddr>,
+
+    /// Enable verbose (TRACE) logging. Defaults to INFO.
+    #[arg(long, env = "NIUM_NTPD_DEBUG")]
+    debug: bool,
+
+    /// Number of SO_REUSEPORT worker tasks spawned per IPv4 bind address.
+    #[arg(long, default_value_t = 4, env = "NIUM_NTPD_IPV4_THREADS")]
+    pub ipv4_threads: usize,
+
+    /// Number of SO_REUSEPORT worker tasks spawned per IPv6 bind address.
+    #[arg(long, default_value_t = 2, env = "NIUM_NTPD_IPV6_THREADS")]
+    pub ipv6_threads: usize,
+
+    /// Max requests per second a single client IP may send before being rate-limited
+    /// with a Kiss-o'-Death RATE packet.
+    #[arg(long, default_value_t = 10, env = "NIUM_NTPD_RATE_LIMIT_PPS")]
+    pub rate_limit_pps: u32,
+
+    /// If non-empty, only these client IPs are served; everyone else gets Kiss-o'-Death RSTR.
+    #[arg(long = "allow", env = "NIUM_NTPD_ALLOW", value_delimiter = ' ')]
+    pub allow_list: Vec<IpAddr>,
+
+    /// Client IPs that are always refused with Kiss-o'-Death DENY.
+    #[arg(long = "deny", env = "NIUM_NTPD_DENY", value_delimiter = ' ')]
+    pub deny_list: Vec<IpAddr>,
+
+    /// Unprivileged user to drop to once every socket is bound.
+    #[arg(long, default_value = "nobody", env = "NIUM_NTPD_USER")]
+    pub user: String,
+
+    /// Unprivileged group to drop to once every socket is bound.
+    #[arg(long, default_value = "nogroup", env = "NIUM_NTPD_GROUP")]
+    pub group: String,
+
+    /// Upstream NTP servers (host:port) to mirror time from. Empty means serve the local wall
+    /// clock only.
+    #[arg(long = "upstream", env = "NIUM_NTPD_UPSTREAM", value_delimiter = ' ')]
+    pub upstream_servers: Vec<SocketAddr>,
+}
+
+impl Config {
+    /// Addresses to bind, falling back to [`DEFAULT_BIND_ADDRS`] when none were supplied.
+    pub fn bind_addresses(&self) -> Vec<SocketAddr> {
+        if self.bind.is_empty() {
+            DEFAULT_BIND_ADDRS
+                .iter()
+                .map(|addr| addr.parse().expect("default bind address is valid"))
+                .collect()
+        } else {
+            self.bind.clone()
+        }
+    }
+
+    /// Max `tracing` verbosity to log at.
+    pub fn log_level(&self) -> Level {
+        if self.debug {
+            Level::TRACE
+        } else {
+            Level::INFO
+        }
+    }
+}
@@ -1,33 +1,73 @@
 #![allow(dead_code)]
 #![allow(clippy::upper_case_acronyms)]
 
+mod access_control;
+mod config;
 mod ntp_packet;
+mod upstream;
 
+use crate::access_control::{AccessControl, AccessDecision, SharedAccessControl};
+use crate::config::Config;
 use crate::ntp_packet::{ExternalReferenceSource, LeapIndicator, NtpMessage, NtpServerResponse, NtpTimestamp, ReferenceIdentifier, Stratum};
+use crate::upstream::{run_upstream_sync, SharedUpstreamSync, UpstreamSync};
+use anyhow::Context;
 use bytes::BytesMut;
-use chrono::{Utc};
+use clap::Parser;
+use privdrop::PrivDrop;
+use socket2::{Domain, Protocol, Socket, Type};
 use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
 use tokio::net::UdpSocket;
+use tokio::sync::Mutex;
 use tracing::{debug, error, info, trace, Level};
 
-fn tracing() {
+/// How often the upstream sync task polls its configured servers.
+const UPSTREAM_POLL_INTERVAL: Duration = Duration::from_secs(64);
+
+fn tracing(max_level: Level) {
     tracing_subscriber::fmt()
-        .with_max_level(Level::TRACE)
+        .with_max_level(max_level)
         .with_target(true)
         .init();
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    tracing();
-
-    // Specify the address to bind to
-    let addr = "0.0.0.0:123".parse::<SocketAddr>()?;
+/// Binds a UDP socket to `addr` with `SO_REUSEPORT` so the kernel load-balances datagrams
+/// across every worker bound to the same address.
+fn bind_reuseport(addr: SocketAddr) -> anyhow::Result<UdpSocket> {
+    let domain = if addr.is_ipv6() { Domain::IPV6 } else { Domain::IPV4 };
+    let socket = Socket::new(domain, Type::DGRAM, Some(Protocol::UDP))?;
+    if addr.is_ipv6() {
+        // Keep the IPv6 listener from also accepting IPv4-mapped traffic, which would
+        // otherwise collide with the dedicated IPv4 workers bound to the same port.
+        socket.set_only_v6(true)?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.set_reuse_port(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    Ok(UdpSocket::from_std(socket.into())?)
+}
 
-    // Create a UDP socket and bind it to the specified address
-    let socket = UdpSocket::bind(&addr).await?;
-    info!("Listening on: {}", addr);
+/// Drops from root to `user`/`group` so the process no longer holds the privileges it
+/// needed only to bind port 123. Must run exactly once, after every socket is bound.
+fn drop_privileges(user: &str, group: &str) -> anyhow::Result<()> {
+    PrivDrop::default()
+        .user(user)
+        .group(group)
+        .apply()
+        .with_context(|| format!("failed to drop privileges to {user}:{group}"))
+}
 
+/// Receives, parses and answers NTP requests on `socket` until an I/O error occurs.
+///
+/// Multiple workers run this independently against sockets bound to the same address;
+/// `upstream_sync` is only ever read here, never written.
+async fn serve(
+    socket: UdpSocket,
+    upstream_sync: SharedUpstreamSync,
+    access_control: SharedAccessControl,
+) -> anyhow::Result<()> {
     // Create a buffer to store incoming data
     let mut buf = BytesMut::with_capacity(512);
     buf.resize(512, 0);
@@ -36,8 +76,6 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         // Receive data into the buffer
         let (size, peer) = socket.recv_from(&mut buf).await?;
 
-        let receive_timestamp = Utc::now();
-
         // Handle the received data
         let mut data = buf.split_to(size);
         // Resize the buffer to make sure data can continuously be received
@@ -51,45 +89,44 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 // For example, you can print the received data
                 debug!("Received {} bytes from {}: {:?}", size, peer, data);
 
-                // Some example alternate settings to mess around are defined below the value it
-                // belongs to. To change it, comment the value out and uncomment the value below it.
+                let decision = access_control.lock().await.check(peer.ip());
+                let sync = upstream_sync.lock().await;
+                let (leap_indicator, stratum, reference_identifier, root_delay, root_dispersion) = match decision {
+                    AccessDecision::Allow => (
+                        LeapIndicator::NoWarning,
+                        sync.stratum(),
+                        ReferenceIdentifier::Primary(Some(ExternalReferenceSource::GPS)),
+                        sync.root_delay(),
+                        sync.root_dispersion(),
+                    ),
+                    AccessDecision::Kiss(kod) => {
+                        debug!("Kiss-o'-Death {:?} for {}", kod, peer);
+                        (
+                            LeapIndicator::AlarmConditionClockNotSynchronised,
+                            Stratum::KissODeathMessage,
+                            ReferenceIdentifier::KissODeath(kod),
+                            0,
+                            0,
+                        )
+                    }
+                };
                 let server_response = NtpServerResponse {
-                    leap_indicator: LeapIndicator::NoWarning,
+                    leap_indicator,
                     version_number: packet.vn,
-                    stratum: Stratum::SecondaryReference,
+                    stratum,
                     poll_interval: 4,
                     precision: -6,
-                    root_delay: 50,
-                    root_dispersion: 20,
-                    reference_identifier: ReferenceIdentifier::Primary(Some(ExternalReferenceSource::GPS)),
-                    reference_timestamp: NtpTimestamp(Utc::now().naive_utc()),
-                    // NtpTimestamp(Utc::now() - Duration::hours(6) - Duration::seconds(3)),
+                    root_delay,
+                    root_dispersion,
+                    reference_identifier,
+                    reference_timestamp: NtpTimestamp(sync.now()),
                     originate_timestamp: Some(packet.transmit_timestamp),
-                    receive_timestamp: NtpTimestamp(receive_timestamp.naive_utc()),
-                    // NtpTimestamp(receive_timestamp - Duration::hours(6)),
-                    transmit_timestamp: Some(NtpTimestamp(Utc::now().naive_utc())),
-                    // Some(NtpTimestamp(Utc::now() - Duration::hours(6))),
+                    receive_timestamp: NtpTimestamp(sync.now()),
+                    transmit_timestamp: Some(NtpTimestamp(sync.now())),
                 };
+                drop(sync);
                 let response = NtpMessage::new_server_response(server_response);
 
-                // The code below can be used to create a response that doesn't abide by a server's
-                // rules
-                // let response = NtpMessage {
-                //     li: LeapIndicator::NoWarning,
-                //     vn: VersionNumber::One,
-                //     mode: Mode::Reserved,
-                //     stratum: Stratum::KissODeathMessage,
-                //     poll_interval: 0,
-                //     precision: 0,
-                //     root_delay: 0,
-                //     root_dispersion: 0,
-                //     reference_identifier: Some(ReferenceIdentifier::Primary(Some(ExternalReferenceSource::GPS))),
-                //     reference_timestamp: Some(NtpTimestamp(Utc::now())),
-                //     originate_timestamp: Some(packet.transmit_timestamp),
-                //     receive_timestamp: Some(NtpTimestamp(receive_timestamp)),
-                //     transmit_timestamp: NtpTimestamp(Utc::now()),
-                // };
-
                 trace!("About to send: {response:?}");
                 socket.send_to(&response.to_bytes(), peer).await?;
                 trace!("Successfully sent response");
@@ -98,3 +135,45 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         };
     }
 }
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let config = Config::parse();
+    tracing(config.log_level());
+
+    let upstream_sync: SharedUpstreamSync = Arc::new(Mutex::new(UpstreamSync::default()));
+    tokio::spawn(run_upstream_sync(
+        config.upstream_servers.clone(),
+        upstream_sync.clone(),
+        UPSTREAM_POLL_INTERVAL,
+    ));
+
+    let access_control: SharedAccessControl = Arc::new(Mutex::new(AccessControl::new(
+        config.rate_limit_pps,
+        config.allow_list.clone(),
+        config.deny_list.clone(),
+    )));
+
+    let mut sockets = Vec::new();
+    for addr in config.bind_addresses() {
+        let workers_for_addr = if addr.is_ipv6() { config.ipv6_threads } else { config.ipv4_threads };
+        for _ in 0..workers_for_addr {
+            sockets.push(bind_reuseport(addr)?);
+        }
+        info!("Listening on {} ({} worker(s))", addr, workers_for_addr);
+    }
+
+    // All sockets are bound; we no longer need root.
+    drop_privileges(&config.user, &config.group)?;
+
+    let workers: Vec<_> = sockets
+        .into_iter()
+        .map(|socket| tokio::spawn(serve(socket, upstream_sync.clone(), access_control.clone())))
+        .collect();
+
+    for worker in workers {
+        worker.await??;
+    }
+
+    Ok(())
+}
@@ -1,6 +1,11 @@
-use anyhow::bail;
 use bytes::{Buf, BytesMut};
-use chrono::{NaiveDateTime, TimeZone, Utc};
+use chrono::{NaiveDateTime, Utc};
+use nom::bits::bits;
+use nom::bits::complete::take as take_bits;
+use nom::bytes::complete::take;
+use nom::number::complete::{be_i32, be_i8, be_u32, u8 as byte};
+use nom::sequence::tuple;
+use nom::IResult;
 use std::net::Ipv4Addr;
 use std::str;
 use std::str::FromStr;
@@ -126,14 +131,32 @@ pub enum NtpTimestampError {
     Invalid,
 }
 
-impl TryFrom<&mut BytesMut> for NtpTimestamp {
-    type Error = NtpTimestampError;
+/// Seconds between the NTP epoch (1900-01-01) and the Unix epoch (1970-01-01).
+const NTP_UNIX_EPOCH_OFFSET: i64 = 2208988800;
 
-    fn try_from(value: &mut BytesMut) -> Result<Self, Self::Error> {
-        trace!("ntp_timestamp: {:?}", value);
-        let seconds = value.split_to(4).get_u32() as i64;
-        let fraction = value.split_to(4).get_u32();
-        let nano_seconds = pad_int(fraction as isize, 9);
+/// Length of one NTP 32-bit seconds era (the field wraps every 2^32 seconds, i.e. around 2036-02-07).
+const NTP_ERA_LENGTH: i64 = 1 << 32;
+
+/// Converts an NTP Q0.32 fraction field into nanoseconds, rounded to the nearest nanosecond:
+/// `nanos = round(fraction * 1e9 / 2^32)`. Clamped to 999_999_999 because rounding the topmost
+/// fraction values (0xFFFFFFFE, 0xFFFFFFFF) would otherwise overflow to 1_000_000_000, which is
+/// outside `NaiveDateTime`'s valid subsecond-nanos range.
+fn ntp_fraction_to_nanos(fraction: u32) -> u32 {
+    let nanos = ((fraction as u64 * 1_000_000_000) + (1 << 31)) >> 32;
+    nanos.min(999_999_999) as u32
+}
+
+/// Converts nanoseconds into an NTP Q0.32 fraction field, rounded to the nearest fraction tick:
+/// `fraction = round(nanos * 2^32 / 1e9)`.
+fn nanos_to_ntp_fraction(nanos: u32) -> u32 {
+    ((((nanos as u64) << 32) + 500_000_000) / 1_000_000_000) as u32
+}
+
+impl NtpTimestamp {
+    /// Converts a raw NTP 64-bit timestamp (32-bit seconds since 1900, Q0.32 fraction)
+    /// into a `NaiveDateTime`, guarding against the 2036 era rollover.
+    fn from_raw(seconds: u32, fraction: u32) -> Result<Self, NtpTimestampError> {
+        let nano_seconds = ntp_fraction_to_nanos(fraction);
 
         trace!("Seconds: {}, fraction: {}, nanoseconds: {}", seconds, fraction, nano_seconds);
 
@@ -141,10 +164,14 @@ impl TryFrom<&mut BytesMut> for NtpTimestamp {
             return Err(NtpTimestampError::Zero);
         }
 
-        // Might be wrong
-        let seconds_unix_format = seconds - 2208988800;
+        let mut seconds_unix_format = seconds as i64 - NTP_UNIX_EPOCH_OFFSET;
+        // Guard against the 2036 era rollover: a raw era-0 interpretation that lands
+        // before the Unix epoch actually means the 32-bit seconds field already wrapped.
+        if seconds_unix_format < 0 {
+            seconds_unix_format += NTP_ERA_LENGTH;
+        }
 
-        let timestamp = NaiveDateTime::from_timestamp_opt(seconds_unix_format, nano_seconds as u32);
+        let timestamp = NaiveDateTime::from_timestamp_opt(seconds_unix_format, nano_seconds);
 
         trace!(
             "Seconds_unix: {}, seconds_fraction: {} Timestamp: {:?}",
@@ -153,19 +180,25 @@ impl TryFrom<&mut BytesMut> for NtpTimestamp {
             timestamp
         );
 
-        match timestamp {
-            Some(ts) => Ok(NtpTimestamp(ts)),
-            None => {
-                Err(NtpTimestampError::Invalid)
-            }
-        }
+        timestamp.map(NtpTimestamp).ok_or(NtpTimestampError::Invalid)
+    }
+}
+
+impl TryFrom<&mut BytesMut> for NtpTimestamp {
+    type Error = NtpTimestampError;
+
+    fn try_from(value: &mut BytesMut) -> Result<Self, Self::Error> {
+        trace!("ntp_timestamp: {:?}", value);
+        let seconds = value.split_to(4).get_u32();
+        let fraction = value.split_to(4).get_u32();
+        Self::from_raw(seconds, fraction)
     }
 }
 
 impl NtpTimestamp {
     fn to_bytes(&self) -> [u8; 8] {
-        let timestamp: u32 = (self.0.timestamp() + 2208988800) as u32;
-        let fraction = self.0.timestamp_subsec_nanos();
+        let timestamp: u32 = (self.0.timestamp() + NTP_UNIX_EPOCH_OFFSET) as u32;
+        let fraction = nanos_to_ntp_fraction(self.0.timestamp_subsec_nanos());
 
         trace!("Timestamp: {:?} Fraction: {:?}", timestamp, fraction);
 
@@ -190,6 +223,13 @@ impl NtpTimestamp {
     }
 }
 
+/// An NTPv4 extension field (RFC 7822) found after the fixed 48-byte header.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExtensionField {
+    pub field_type: u16,
+    pub value: Vec<u8>,
+}
+
 #[derive(Debug)]
 pub struct NtpMessage {
     pub li: LeapIndicator,
@@ -205,6 +245,12 @@ pub struct NtpMessage {
     pub originate_timestamp: Option<NtpTimestamp>,
     pub receive_timestamp: Option<NtpTimestamp>,
     pub transmit_timestamp: NtpTimestamp,
+    pub extensions: Vec<ExtensionField>,
+    /// The stratum byte as it came off the wire, before bucketing into [`Stratum`]. [`Stratum`]
+    /// only distinguishes 2-15 as a single `SecondaryReference` variant, so callers that need
+    /// the upstream's actual numeric stratum (e.g. to compute their own stratum as upstream + 1)
+    /// must read this instead of `stratum`.
+    pub stratum_raw: u8,
 }
 
 #[derive(Debug)]
@@ -223,15 +269,6 @@ pub struct NtpServerResponse {
     pub transmit_timestamp: Option<NtpTimestamp>,
 }
 
-fn pad_int(mut integer: isize, expected_digits: i32) -> isize {
-    let digit_length = integer.to_string().len() as i32;
-    let shifter = 10_f64.powi(expected_digits - digit_length);
-
-    integer = ((integer as f64) * shifter) as isize;
-
-    integer
-}
-
 impl NtpMessage {
     pub fn to_bytes(&self) -> [u8; 48] {
         let mut bytes = [0; 48];
@@ -324,106 +361,307 @@ impl NtpMessage {
                 Some(ts) => ts,
                 None => NtpTimestamp(Utc::now().naive_utc()),
             },
+            extensions: Vec::new(),
+            stratum_raw: res.stratum as u8,
+        }
+    }
+}
+
+/// Errors produced while decoding an [`NtpMessage`], naming the field and byte offset
+/// (from the start of the packet) where decoding failed.
+#[derive(Error, Debug)]
+pub enum NtpParseError {
+    #[error("packet is too small: got {actual} bytes, need at least {minimum}")]
+    TooShort { actual: usize, minimum: usize },
+    #[error("unable to parse leap indicator at offset {offset}")]
+    LeapIndicator { offset: usize },
+    #[error("unable to parse version number at offset {offset}")]
+    VersionNumber { offset: usize },
+    #[error("unable to parse mode at offset {offset}")]
+    Mode { offset: usize },
+    #[error("reference identifier at offset {offset} is not valid UTF-8")]
+    ReferenceIdentifierEncoding { offset: usize },
+    #[error("invalid Kiss-o'-Death identifier at offset {offset}")]
+    KissODeathIdentifier { offset: usize },
+    #[error("unable to parse {field} timestamp at offset {offset}")]
+    Timestamp { field: &'static str, offset: usize },
+    #[error("malformed packet at offset {offset}")]
+    Malformed { offset: usize },
+}
+
+/// Bit-level parser for the first header byte: 2-bit LI, 3-bit VN, 3-bit Mode.
+fn parse_flags(input: &[u8]) -> IResult<&[u8], (u8, u8, u8)> {
+    bits::<_, _, nom::error::Error<(&[u8], usize)>, _, _>(tuple((
+        take_bits(2usize),
+        take_bits(3usize),
+        take_bits(3usize),
+    )))(input)
+}
+
+/// Parses a raw 64-bit NTP timestamp (32-bit seconds, 32-bit Q0.32 fraction) as two `u32`s.
+fn parse_raw_timestamp(input: &[u8]) -> IResult<&[u8], (u32, u32)> {
+    tuple((be_u32, be_u32))(input)
+}
+
+/// Bytes of the fixed 48-byte NTP header, already split off the flags/stratum/etc. fields.
+struct RawHeader<'a> {
+    li: u8,
+    vn: u8,
+    mode: u8,
+    stratum: u8,
+    poll_interval: u8,
+    precision: i8,
+    root_delay: i32,
+    root_dispersion: u32,
+    reference_identifier: &'a [u8],
+    reference_timestamp: (u32, u32),
+    originate_timestamp: (u32, u32),
+    receive_timestamp: (u32, u32),
+    transmit_timestamp: (u32, u32),
+}
+
+/// Declaratively parses the fixed 48-byte NTP header, leaving any trailing bytes (NTPv4
+/// extension fields) in the returned remainder.
+fn parse_header(input: &[u8]) -> IResult<&[u8], RawHeader<'_>> {
+    let (input, (li, vn, mode)) = parse_flags(input)?;
+    let (input, stratum) = byte(input)?;
+    let (input, poll_interval) = byte(input)?;
+    let (input, precision) = be_i8(input)?;
+    let (input, root_delay) = be_i32(input)?;
+    let (input, root_dispersion) = be_u32(input)?;
+    let (input, reference_identifier) = take(4usize)(input)?;
+    let (input, reference_timestamp) = parse_raw_timestamp(input)?;
+    let (input, originate_timestamp) = parse_raw_timestamp(input)?;
+    let (input, receive_timestamp) = parse_raw_timestamp(input)?;
+    let (input, transmit_timestamp) = parse_raw_timestamp(input)?;
+
+    Ok((
+        input,
+        RawHeader {
+            li,
+            vn,
+            mode,
+            stratum,
+            poll_interval,
+            precision,
+            root_delay,
+            root_dispersion,
+            reference_identifier,
+            reference_timestamp,
+            originate_timestamp,
+            receive_timestamp,
+            transmit_timestamp,
+        },
+    ))
+}
+
+/// Parses RFC 7822 extension fields (`type: u16, length: u16, value: length - 4 bytes`)
+/// out of the bytes following the fixed header. Fields with an implausible length are
+/// dropped along with the rest of the trailing bytes rather than causing a decode error,
+/// since extensions are additive and a malformed one shouldn't sink the whole packet.
+fn parse_extension_fields(mut input: &[u8]) -> Vec<ExtensionField> {
+    let mut fields = Vec::new();
+    while input.len() >= 4 {
+        let field_type = u16::from_be_bytes([input[0], input[1]]);
+        let length = u16::from_be_bytes([input[2], input[3]]) as usize;
+        if length < 4 || length > input.len() {
+            break;
+        }
+        fields.push(ExtensionField {
+            field_type,
+            value: input[4..length].to_vec(),
+        });
+        input = &input[length..];
+    }
+    fields
+}
+
+fn decode_reference_identifier(
+    stratum: Stratum,
+    raw: &[u8],
+) -> Result<ReferenceIdentifier, NtpParseError> {
+    match stratum {
+        Stratum::KissODeathMessage => {
+            let as_string = str::from_utf8(raw)
+                .map_err(|_| NtpParseError::ReferenceIdentifierEncoding { offset: 12 })?;
+            trace!("Reference identifier as utf8 string: {as_string:?}");
+            let kod_identifier = KissODeathIdentifier::from_str(as_string)
+                .map_err(|_| NtpParseError::KissODeathIdentifier { offset: 12 })?;
+            trace!("Kiss-O-Death Identifier: {kod_identifier:?}");
+            Ok(ReferenceIdentifier::KissODeath(kod_identifier))
+        }
+        Stratum::PrimaryReference => {
+            let as_string = str::from_utf8(raw)
+                .map_err(|_| NtpParseError::ReferenceIdentifierEncoding { offset: 12 })?;
+            trace!("Reference identifier as utf8 string: {as_string:?}");
+            let reference_source = ExternalReferenceSource::from_str(as_string).ok();
+            trace!("Reference source: {reference_source:?}");
+            Ok(ReferenceIdentifier::Primary(reference_source))
+        }
+        Stratum::SecondaryReference => {
+            Ok(ReferenceIdentifier::UnknownIpVersion(u32::from_be_bytes(
+                raw.try_into().map_err(|_| NtpParseError::Malformed { offset: 12 })?,
+            )))
         }
+        Stratum::Reserved => Ok(ReferenceIdentifier::ReservedStratum(u32::from_be_bytes(
+            raw.try_into().map_err(|_| NtpParseError::Malformed { offset: 12 })?,
+        ))),
     }
 }
 
 impl TryFrom<&mut BytesMut> for NtpMessage {
-    type Error = anyhow::Error;
+    type Error = NtpParseError;
 
     fn try_from(value: &mut BytesMut) -> Result<Self, Self::Error> {
         if value.len() < 48 {
-            bail!("Packet is too small");
+            return Err(NtpParseError::TooShort { actual: value.len(), minimum: 48 });
         }
-        let flags = value.split_to(1).get_u8();
-        let li = match LeapIndicator::from_repr(&flags >> 6) {
-            Some(li) => li,
-            None => bail!("Unable to parse LeapIndicator"),
-        };
-        let vn = match VersionNumber::from_repr((&flags & 0b0011_1000) >> 3) {
-            Some(vn) => vn,
-            None => bail!("Unable to parse VersionNumber"),
-        };
-        let mode = match Mode::from_repr(&flags & 0b0000_0111) {
-            Some(mode) => mode,
-            None => bail!("Unable to parse Mode"),
-        };
+
+        let (remainder, raw) = parse_header(&value[..])
+            .map_err(|_| NtpParseError::Malformed { offset: 0 })?;
+
+        let li = LeapIndicator::from_repr(raw.li).ok_or(NtpParseError::LeapIndicator { offset: 0 })?;
+        let vn = VersionNumber::from_repr(raw.vn).ok_or(NtpParseError::VersionNumber { offset: 0 })?;
+        let mode = Mode::from_repr(raw.mode).ok_or(NtpParseError::Mode { offset: 0 })?;
         trace!("VersionNumber: {vn:?}");
-        let stratum = Stratum::from(value.split_to(1).get_u8());
+        let stratum = Stratum::from(raw.stratum);
         trace!("Stratum: {stratum:?}");
-        let poll_interval = value.split_to(1).get_u8();
-        let precision = value.split_to(1).get_i8();
-        let root_delay = value.split_to(4).get_i32();
-        let root_dispersion = value.split_to(4).get_u32();
-        let reference_identifier = {
-            let mut slice = value.split_to(4);
-            if mode == Mode::Client {
-                None
-            } else {
-                Some(match stratum {
-                    Stratum::KissODeathMessage => {
-                        let as_string = str::from_utf8(slice.as_ref())?;
-                        trace!(
-                            "Reference identifier as utf8 string: {as_string:?}. Mode: {mode:?}"
-                        );
-                        let kod_identifier = match KissODeathIdentifier::from_str(as_string) {
-                            Ok(rs) => rs,
-                            Err(_) => bail!("Invalid Kiss-O-Death Identifier"),
-                        };
-                        trace!("Kiss-O-Death Identifier: {kod_identifier:?}");
-                        ReferenceIdentifier::KissODeath(kod_identifier)
-                    }
-                    Stratum::PrimaryReference => {
-                        let as_string = str::from_utf8(slice.as_ref())?;
-                        trace!(
-                            "Reference identifier as utf8 string: {as_string:?}. Mode: {mode:?}"
-                        );
-                        let reference_source = match ExternalReferenceSource::from_str(as_string) {
-                            Ok(rs) => Some(rs),
-                            Err(_) => None,
-                        };
-                        trace!("Reference source: {reference_source:?}");
-                        ReferenceIdentifier::Primary(reference_source)
-                    }
-                    Stratum::SecondaryReference => {
-                        ReferenceIdentifier::UnknownIpVersion(slice.get_u32())
-                    }
-                    Stratum::Reserved => ReferenceIdentifier::ReservedStratum(slice.get_u32()),
-                })
-            }
-        };
-        let reference_timestamp: Option<NtpTimestamp> =
-            match NtpTimestamp::try_from(&mut value.split_to(8)) {
-                Ok(ts) => Some(ts),
-                Err(_) => None,
-            };
-        let originate_timestamp = match NtpTimestamp::try_from(&mut value.split_to(8)) {
-            Ok(ts) => Some(ts),
-            Err(_) => None,
-        };
-        let receive_timestamp = match NtpTimestamp::try_from(&mut value.split_to(8)) {
-            Ok(ts) => Some(ts),
-            Err(_) => None,
-        };
-        let transmit_timestamp = match NtpTimestamp::try_from(&mut value.split_to(8)) {
-            Ok(ts) => ts,
-            Err(_) => bail!("Unable to parse transmit timestamp"),
+
+        let reference_identifier = if mode == Mode::Client {
+            None
+        } else {
+            Some(decode_reference_identifier(stratum, raw.reference_identifier)?)
         };
 
+        let reference_timestamp =
+            NtpTimestamp::from_raw(raw.reference_timestamp.0, raw.reference_timestamp.1).ok();
+        let originate_timestamp =
+            NtpTimestamp::from_raw(raw.originate_timestamp.0, raw.originate_timestamp.1).ok();
+        let receive_timestamp =
+            NtpTimestamp::from_raw(raw.receive_timestamp.0, raw.receive_timestamp.1).ok();
+        let transmit_timestamp =
+            NtpTimestamp::from_raw(raw.transmit_timestamp.0, raw.transmit_timestamp.1)
+                .map_err(|_| NtpParseError::Timestamp { field: "transmit_timestamp", offset: 40 })?;
+
+        let extensions = parse_extension_fields(remainder);
+        if !extensions.is_empty() {
+            trace!("Parsed {} NTPv4 extension field(s)", extensions.len());
+        }
+
         Ok(NtpMessage {
             li,
             vn,
             mode,
             stratum,
-            poll_interval,
-            precision,
-            root_delay,
-            root_dispersion,
+            poll_interval: raw.poll_interval,
+            precision: raw.precision,
+            root_delay: raw.root_delay,
+            root_dispersion: raw.root_dispersion,
             reference_identifier,
             reference_timestamp,
             originate_timestamp,
             receive_timestamp,
             transmit_timestamp,
+            extensions,
+            stratum_raw: raw.stratum,
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roundtrip(date: NaiveDateTime) -> NaiveDateTime {
+        let bytes = NtpTimestamp(date).to_bytes();
+        let mut buf = BytesMut::from(&bytes[..]);
+        NtpTimestamp::try_from(&mut buf).unwrap().0
+    }
+
+    #[test]
+    fn fraction_roundtrip_is_bit_exact() {
+        for nanos in [0, 1, 500_000_000, 999_999_999, 123_456_789, 250_000_000, 750_000_001] {
+            let date = NaiveDateTime::from_timestamp_opt(1_700_000_000, nanos).unwrap();
+            let result = roundtrip(date);
+            assert_eq!(
+                result.timestamp_subsec_nanos(),
+                nanos,
+                "subsec nanos did not round-trip for input {nanos}"
+            );
+            assert_eq!(result.timestamp(), date.timestamp());
+        }
+    }
+
+    #[test]
+    fn known_fraction_conversion() {
+        // 0.5 seconds is exactly half of the 32-bit fraction range.
+        assert_eq!(ntp_fraction_to_nanos(1 << 31), 500_000_000);
+        assert_eq!(nanos_to_ntp_fraction(500_000_000), 1 << 31);
+    }
+
+    #[test]
+    fn topmost_fraction_values_round_down_to_a_valid_nanos() {
+        // Rounding 0xFFFFFFFE/0xFFFFFFFF naively overflows to 1_000_000_000, which
+        // NaiveDateTime::from_timestamp_opt rejects as an invalid subsecond-nanos value.
+        assert_eq!(ntp_fraction_to_nanos(0xFFFFFFFE), 999_999_999);
+        assert_eq!(ntp_fraction_to_nanos(0xFFFFFFFF), 999_999_999);
+    }
+
+    // A typical Mode::Client request as sent by a stock `ntpdate`-style client: LI=0,
+    // VN=4, Mode=3, Stratum=0, Poll=6, Precision=-20, all timestamps zero except a
+    // transmit timestamp of 2023-01-01T00:00:00Z with no fractional part.
+    const CLIENT_REQUEST: [u8; 48] = [
+        0x23, 0x00, 0x06, 0xec, // flags, stratum, poll, precision
+        0x00, 0x00, 0x00, 0x00, // root delay
+        0x00, 0x00, 0x00, 0x00, // root dispersion
+        0x00, 0x00, 0x00, 0x00, // reference identifier
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // reference timestamp
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // originate timestamp
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // receive timestamp
+        0xe7, 0x5b, 0x4b, 0x80, 0x00, 0x00, 0x00, 0x00, // transmit timestamp
+    ];
+
+    #[test]
+    fn parses_real_client_request() {
+        let mut buf = BytesMut::from(&CLIENT_REQUEST[..]);
+        let message = NtpMessage::try_from(&mut buf).unwrap();
+
+        assert!(matches!(message.li, LeapIndicator::NoWarning));
+        assert!(matches!(message.vn, VersionNumber::Four));
+        assert_eq!(message.mode, Mode::Client);
+        assert!(message.reference_identifier.is_none());
+        assert_eq!(message.poll_interval, 6);
+        assert_eq!(message.precision, -20);
+        assert_eq!(message.transmit_timestamp.0.timestamp(), 1_672_531_200);
+        assert_eq!(message.transmit_timestamp.0.timestamp_subsec_nanos(), 0);
+        assert!(message.extensions.is_empty());
+    }
+
+    #[test]
+    fn client_request_roundtrips_through_to_bytes() {
+        let mut buf = BytesMut::from(&CLIENT_REQUEST[..]);
+        let message = NtpMessage::try_from(&mut buf).unwrap();
+        assert_eq!(message.to_bytes(), CLIENT_REQUEST);
+    }
+
+    #[test]
+    fn trailing_bytes_are_parsed_as_extension_fields() {
+        let mut packet = CLIENT_REQUEST.to_vec();
+        // A 4-byte extension field: type 0x0002, length 4, empty value.
+        packet.extend_from_slice(&[0x00, 0x02, 0x00, 0x04]);
+        let mut buf = BytesMut::from(&packet[..]);
+
+        let message = NtpMessage::try_from(&mut buf).unwrap();
+
+        assert_eq!(message.extensions.len(), 1);
+        assert_eq!(message.extensions[0].field_type, 0x0002);
+        assert!(message.extensions[0].value.is_empty());
+    }
+
+    #[test]
+    fn too_short_packet_is_rejected() {
+        let mut buf = BytesMut::from(&CLIENT_REQUEST[..47]);
+        let err = NtpMessage::try_from(&mut buf).unwrap_err();
+        assert!(matches!(err, NtpParseError::TooShort { actual: 47, minimum: 48 }));
+    }
+}
@@ -0,0 +1,171 @@
+use crate::ntp_packet::{LeapIndicator, Mode, NtpMessage, NtpTimestamp, Stratum, VersionNumber};
+use bytes::BytesMut;
+use chrono::{Duration, NaiveDateTime, Utc};
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration as StdDuration;
+use tokio::net::UdpSocket;
+use tokio::sync::Mutex;
+use tokio::time::interval;
+use tracing::{debug, warn};
+
+/// Max number of recent (offset, delay) samples kept across all upstream servers.
+const SAMPLE_HISTORY: usize = 8;
+
+/// Samples whose round-trip delay exceeds this are discarded as unreliable.
+const MAX_ACCEPTABLE_DELAY: Duration = Duration::milliseconds(1000);
+
+#[derive(Debug, Clone, Copy)]
+struct ClockSample {
+    offset: Duration,
+    delay: Duration,
+}
+
+/// Shared, continuously-updated view of "corrected now" derived from upstream NTP servers.
+///
+/// Lives behind an `Arc<Mutex<_>>` so the request-handling loop can read it without
+/// coordinating with the background sync task beyond the lock itself.
+#[derive(Debug, Default)]
+pub struct UpstreamSync {
+    samples: VecDeque<ClockSample>,
+    /// Raw stratum byte reported by the upstream we last accepted a sample from. Kept as the
+    /// wire value rather than [`Stratum`], since [`Stratum`] buckets every value 2-15 into a
+    /// single `SecondaryReference` variant and we need the real number to compute our own.
+    upstream_stratum: Option<u8>,
+    root_delay: i32,
+    root_dispersion: u32,
+}
+
+pub type SharedUpstreamSync = Arc<Mutex<UpstreamSync>>;
+
+impl UpstreamSync {
+    /// Corrected current time, falling back to the local wall clock until a sample is accepted.
+    pub fn now(&self) -> NaiveDateTime {
+        match self.best_sample() {
+            Some(sample) => Utc::now().naive_utc() + sample.offset,
+            None => Utc::now().naive_utc(),
+        }
+    }
+
+    /// The stratum to advertise: one below the upstream we last accepted a sample from, or a
+    /// sane default if unsynced.
+    pub fn stratum(&self) -> Stratum {
+        match self.upstream_stratum {
+            Some(raw) => Stratum::from(derive_stratum(raw)),
+            None => Stratum::SecondaryReference,
+        }
+    }
+
+    pub fn root_delay(&self) -> i32 {
+        self.root_delay
+    }
+
+    pub fn root_dispersion(&self) -> u32 {
+        self.root_dispersion
+    }
+
+    fn best_sample(&self) -> Option<ClockSample> {
+        self.samples.iter().min_by_key(|sample| sample.delay).copied()
+    }
+
+    fn record(&mut self, sample: ClockSample, upstream_stratum: u8, root_delay: i32, root_dispersion: u32) {
+        if self.samples.len() == SAMPLE_HISTORY {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(sample);
+        self.upstream_stratum = Some(upstream_stratum);
+        self.root_delay = root_delay;
+        self.root_dispersion = root_dispersion;
+    }
+}
+
+/// Derives the stratum we advertise from an upstream's raw stratum byte: one level below the
+/// upstream, clamped to 15 (the highest "normal" stratum) so a bad clamp never lands on the
+/// `Reserved` value 16+ that `Stratum` maps to a dead end.
+fn derive_stratum(upstream_stratum: u8) -> u8 {
+    upstream_stratum.saturating_add(1).min(15)
+}
+
+/// Periodically queries `upstreams` and feeds every sample within [`MAX_ACCEPTABLE_DELAY`]
+/// into `sync`, which serves the lowest-delay one.
+///
+/// Returns at once when `upstreams` is empty; otherwise runs until the process exits. A
+/// failure to reach one upstream on a given tick is logged and does not affect the others.
+pub async fn run_upstream_sync(upstreams: Vec<SocketAddr>, sync: SharedUpstreamSync, poll_interval: StdDuration) {
+    if upstreams.is_empty() {
+        return;
+    }
+
+    let mut ticker = interval(poll_interval);
+    loop {
+        ticker.tick().await;
+        for upstream in &upstreams {
+            match query_upstream(*upstream).await {
+                Ok((sample, upstream_stratum, root_delay, root_dispersion)) => {
+                    if sample.delay > MAX_ACCEPTABLE_DELAY {
+                        warn!("Rejecting sample from {upstream}: delay {:?} exceeds threshold", sample.delay);
+                        continue;
+                    }
+                    debug!("Accepted sample from {upstream}: offset {:?}, delay {:?}", sample.offset, sample.delay);
+                    sync.lock().await.record(sample, upstream_stratum, root_delay, root_dispersion);
+                }
+                Err(err) => warn!("Failed to query upstream {upstream}: {err}"),
+            }
+        }
+    }
+}
+
+/// Sends a single Mode::Client request to `upstream` and computes the clock offset/delay
+/// from the four NTP timestamps (T1 originate, T2 server receive, T3 server transmit, T4 our receive).
+async fn query_upstream(upstream: SocketAddr) -> anyhow::Result<(ClockSample, u8, i32, u32)> {
+    let bind_addr = if upstream.is_ipv6() { "[::]:0" } else { "0.0.0.0:0" };
+    let socket = UdpSocket::bind(bind_addr).await?;
+    socket.connect(upstream).await?;
+
+    let t1 = Utc::now().naive_utc();
+    let request = NtpMessage {
+        li: LeapIndicator::NoWarning,
+        vn: VersionNumber::Four,
+        mode: Mode::Client,
+        stratum: Stratum::KissODeathMessage,
+        poll_interval: 4,
+        precision: -6,
+        root_delay: 0,
+        root_dispersion: 0,
+        reference_identifier: None,
+        reference_timestamp: None,
+        originate_timestamp: None,
+        receive_timestamp: None,
+        transmit_timestamp: NtpTimestamp(t1),
+        extensions: Vec::new(),
+        stratum_raw: 0,
+    };
+    socket.send(&request.to_bytes()).await?;
+
+    let mut buf = BytesMut::zeroed(512);
+    let size = tokio::time::timeout(StdDuration::from_secs(5), socket.recv(&mut buf))
+        .await
+        .map_err(|_| anyhow::anyhow!("timed out waiting for a response from {upstream}"))??;
+    let t4 = Utc::now().naive_utc();
+
+    let mut data = buf.split_to(size);
+    let response = NtpMessage::try_from(&mut data)?;
+
+    let t2 = response
+        .receive_timestamp
+        .as_ref()
+        .ok_or_else(|| anyhow::anyhow!("upstream response missing receive timestamp"))?
+        .0;
+    let t3 = response.transmit_timestamp.0;
+
+    let offset = ((t2 - t1) + (t3 - t4)) / 2;
+    let delay = (t4 - t1) - (t3 - t2);
+
+    Ok((
+        ClockSample { offset, delay },
+        response.stratum_raw,
+        response.root_delay,
+        response.root_dispersion,
+    ))
+}